@@ -59,12 +59,94 @@ mkdir -p "${DATA_DIR}"
 cat > "${DATA_DIR}/setup_bash.sh" << 'BASH_EOF'
 # llmc: Bash integration — source this file in your .bashrc
 # Usage: Press Ctrl+E with a natural language description on the command line
+#        Press Alt+E after a command fails to get a corrected one
+#        Press Alt+H on a typed command to see what each part does
+#        Set LLMC_CAPTURE_STDERR=1 to also send the failed command's error output
+#        Set LLMC_SHARE_HISTORY=1 to pass the in-memory history (see history.enabled)
+
+# Aliases and functions, which llmc cannot find on PATH; completion helpers are left out
+_llmc_shell_commands() {
+  compgen -A alias -A function -X '_*'
+}
 
 _ai_cmd_replace() {
   [[ -z "$READLINE_LINE" ]] && return
 
+  local result recent=
+  [[ -n "$LLMC_SHARE_HISTORY" ]] && recent="$(fc -ln -50 2>/dev/null)"
+  result="$(LLMC_WIDGET=1 LLMC_SHELL=bash LLMC_SHELL_COMMANDS="$(_llmc_shell_commands)" LLMC_HISTORY="$recent" llmc "$READLINE_LINE" 2>/dev/tty)"
+
+  if [[ $? -eq 0 ]]; then
+    READLINE_LINE="$result"
+    READLINE_POINT=${#READLINE_LINE}
+  fi
+}
+
+# Remember the last failed command and its exit status for `llmc --fix`
+_llmc_save_status() {
+  _llmc_status=$?
+}
+
+_llmc_precmd() {
+  if [[ -n "$_llmc_stderr_fd" ]]; then
+    exec 2>&"$_llmc_stderr_fd" {_llmc_stderr_fd}>&-
+    unset _llmc_stderr_fd
+  fi
+  local last
+  last="$(HISTTIMEFORMAT= history 1)"
+  last="${last#*[0-9]  }"
+  case "$last" in
+    llmc|llmc\ *) ;;
+    *)
+      export LLMC_LAST_STATUS="$_llmc_status"
+      if [[ "$_llmc_status" -ne 0 ]]; then
+        export LLMC_LAST_COMMAND="$last"
+        if [[ -n "$_llmc_captured" ]]; then
+          export LLMC_LAST_STDERR="$_llmc_stderr_file"
+        else
+          unset LLMC_LAST_STDERR
+        fi
+      else
+        unset LLMC_LAST_COMMAND LLMC_LAST_STDERR
+      fi
+      ;;
+  esac
+  _llmc_captured=
+  _llmc_armed=1
+}
+
+# Runs before each command: tee the stderr of the first one after each prompt
+_llmc_preexec() {
+  [[ -n "$_llmc_armed" && -n "$LLMC_CAPTURE_STDERR" ]] || return 0
+  case "$BASH_COMMAND" in
+    # tee would recreate the file after the EXIT trap removed it
+    llmc|llmc\ *|_ai_cmd_*|_llmc_*|exit|exit\ *|logout) return 0 ;;
+  esac
+  _llmc_armed=
+  # In a pipeline the pipe is already open here, and tee would hold it open
+  [[ "$(HISTTIMEFORMAT= history 1)" == *"|"* ]] && return 0
+  if [[ -z "$_llmc_stderr_file" ]]; then
+    _llmc_stderr_file="$(mktemp "${TMPDIR:-/tmp}/llmc-stderr.XXXXXX")" || return 0
+  fi
+  _llmc_captured=1
+  exec {_llmc_stderr_fd}>&2 2> >(tee "$_llmc_stderr_file" >&2)
+}
+
+_llmc_cleanup() {
+  [[ -n "$_llmc_stderr_file" ]] && rm -f "$_llmc_stderr_file"
+}
+
+# Prepend `fn` to a trap, keeping the previous one given as `trap -p` output
+_llmc_chain_trap() {
+  local fn="$1" signal="$2"
+  eval "set -- $3"
+  [[ "$3" == *"$fn"* ]] && return 0
+  trap "$fn${3:+; $3}" "$signal"
+}
+
+_ai_cmd_fix() {
   local result
-  result="$(LLMC_WIDGET=1 llmc "$READLINE_LINE" 2>/dev/tty)"
+  result="$(LLMC_WIDGET=1 LLMC_SHELL=bash LLMC_SHELL_COMMANDS="$(_llmc_shell_commands)" llmc --fix "$READLINE_LINE" 2>/dev/tty)"
 
   if [[ $? -eq 0 ]]; then
     READLINE_LINE="$result"
@@ -72,18 +154,103 @@ _ai_cmd_replace() {
   fi
 }
 
+_ai_cmd_explain() {
+  [[ -z "$READLINE_LINE" ]] && return
+  LLMC_WIDGET=1 LLMC_SHELL=bash llmc --explain "$READLINE_LINE" >/dev/null 2>/dev/tty
+}
+
+if [[ -n "${bash_preexec_imported:-}${__bp_imported:-}" ]]; then
+  # bash-preexec owns the DEBUG trap and PROMPT_COMMAND; it restores $? for each hook
+  [[ " ${precmd_functions[*]} " == *" _llmc_precmd "* ]] || {
+    precmd_functions+=(_llmc_save_status _llmc_precmd)
+    preexec_functions+=(_llmc_preexec)
+  }
+else
+  # Sourced files and functions do not see an existing DEBUG trap, so it is
+  # chained once from the first prompt
+  _llmc_once='_llmc_chain_trap _llmc_preexec DEBUG "$(trap -p DEBUG)"; _llmc_once='
+  if [[ "$(declare -p PROMPT_COMMAND 2>/dev/null)" == "declare -a"* ]]; then
+    # bash 5.1+ runs every element of an array PROMPT_COMMAND
+    [[ " ${PROMPT_COMMAND[*]} " == *" _llmc_precmd "* ]] ||
+      PROMPT_COMMAND=(_llmc_save_status "${PROMPT_COMMAND[@]}" 'eval "$_llmc_once"' _llmc_precmd)
+  elif [[ "$PROMPT_COMMAND" != *_llmc_precmd* ]]; then
+    PROMPT_COMMAND="_llmc_save_status${PROMPT_COMMAND:+;$PROMPT_COMMAND};eval \"\$_llmc_once\";_llmc_precmd"
+  fi
+fi
+_llmc_chain_trap _llmc_cleanup EXIT "$(trap -p EXIT)"
+
 bind -x '"\C-e": _ai_cmd_replace'
+bind -x '"\ee": _ai_cmd_fix'
+bind -x '"\eh": _ai_cmd_explain'
 BASH_EOF
 
 cat > "${DATA_DIR}/setup_zsh.sh" << 'ZSH_EOF'
 # llmc: Zsh integration — source this file in your .zshrc
 # Usage: Press Ctrl+E with a natural language description on the command line
+#        Press Alt+E after a command fails to get a corrected one
+#        Press Alt+H on a typed command to see what each part does, unless
+#        Alt+H is taken (it runs run-help by default; see the end of this file)
+#        Set LLMC_CAPTURE_STDERR=1 to also send the failed command's error output
+#        Set LLMC_SHARE_HISTORY=1 to pass the in-memory history (see history.enabled)
 
 _ai_cmd_replace() {
   [[ -z "$BUFFER" ]] && return
 
+  local result recent=
+  [[ -n "$LLMC_SHARE_HISTORY" ]] && recent="$(fc -ln -50 2>/dev/null)"
+  result="$(LLMC_WIDGET=1 LLMC_SHELL=zsh LLMC_SHELL_COMMANDS="${(k)aliases} ${(k)functions:#_*}" LLMC_HISTORY="$recent" llmc "$BUFFER" 2>/dev/tty)"
+
+  if [[ $? -eq 0 ]]; then
+    BUFFER="$result"
+    CURSOR=${#BUFFER}
+  fi
+  zle redisplay
+}
+
+# Remember the last failed command and its exit status for `llmc --fix`
+_llmc_preexec() {
+  case "$1" in
+    llmc|llmc\ *) return ;;
+  esac
+  _llmc_command="$1"
+  _llmc_captured=
+  if [[ -n "$LLMC_CAPTURE_STDERR" ]]; then
+    if [[ -z "$_llmc_stderr_file" ]]; then
+      _llmc_stderr_file="$(mktemp "${TMPDIR:-/tmp}/llmc-stderr.XXXXXX")" || return
+    fi
+    _llmc_captured=1
+    exec {_llmc_stderr_fd}>&2 2> >(tee "$_llmc_stderr_file" >&2)
+  fi
+}
+
+_llmc_precmd() {
+  local exit_status=$?
+  if [[ -n "$_llmc_stderr_fd" ]]; then
+    exec 2>&$_llmc_stderr_fd {_llmc_stderr_fd}>&-
+    unset _llmc_stderr_fd
+  fi
+  [[ -n "$_llmc_command" ]] || return
+  export LLMC_LAST_STATUS=$exit_status
+  if (( exit_status != 0 )); then
+    export LLMC_LAST_COMMAND="$_llmc_command"
+    if [[ -n "$_llmc_captured" ]]; then
+      export LLMC_LAST_STDERR="$_llmc_stderr_file"
+    else
+      unset LLMC_LAST_STDERR
+    fi
+  else
+    unset LLMC_LAST_COMMAND LLMC_LAST_STDERR
+  fi
+  unset _llmc_command
+}
+
+_llmc_zshexit() {
+  [[ -n "$_llmc_stderr_file" ]] && rm -f "$_llmc_stderr_file"
+}
+
+_ai_cmd_fix() {
   local result
-  result="$(LLMC_WIDGET=1 llmc "$BUFFER" 2>/dev/tty)"
+  result="$(LLMC_WIDGET=1 LLMC_SHELL=zsh LLMC_SHELL_COMMANDS="${(k)aliases} ${(k)functions:#_*}" llmc --fix "$BUFFER" 2>/dev/tty)"
 
   if [[ $? -eq 0 ]]; then
     BUFFER="$result"
@@ -92,8 +259,25 @@ _ai_cmd_replace() {
   zle redisplay
 }
 
+_ai_cmd_explain() {
+  [[ -z "$BUFFER" ]] && return
+  zle -I
+  LLMC_WIDGET=1 LLMC_SHELL=zsh llmc --explain "$BUFFER" >/dev/null 2>/dev/tty
+}
+
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec _llmc_preexec
+add-zsh-hook precmd _llmc_precmd
+add-zsh-hook zshexit _llmc_zshexit
+
 zle -N _ai_cmd_replace
+zle -N _ai_cmd_fix
+zle -N _ai_cmd_explain
 bindkey '^e' _ai_cmd_replace
+bindkey '^[e' _ai_cmd_fix
+# Alt+H is run-help by default, which is left alone; bind another key with
+#   bindkey '^[k' _ai_cmd_explain
+[[ "$(bindkey '^[h')" == *" undefined-key" ]] && bindkey '^[h' _ai_cmd_explain
 ZSH_EOF
 
 echo "Installed: ${DATA_DIR}/"